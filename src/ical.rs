@@ -0,0 +1,205 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! Build an RFC 5545 iCalendar feed out of downloaded recordings, so the
+//! output directory can be subscribed to as a calendar of what was recorded
+//! and where it ended up on disk.
+
+use crate::Recording;
+
+const PRODID: &str = "-//movementarian.org//zoom-lomax//EN";
+
+/// Fold a single content line at 75 octets, as required by RFC 5545
+/// section 3.1: continuation lines start with a single space.
+fn fold_line(line: &str) -> String {
+    const LIMIT: usize = 75;
+    let bytes = line.as_bytes();
+
+    if bytes.len() <= LIMIT {
+        return line.to_string();
+    }
+
+    let mut folded = String::new();
+    let mut start = 0;
+
+    while start < bytes.len() {
+        let budget = if start == 0 { LIMIT } else { LIMIT - 1 };
+        let mut end = (start + budget).min(bytes.len());
+
+        // don't split a multi-byte UTF-8 sequence across the fold
+        while end < bytes.len() && (bytes[end] & 0xc0) == 0x80 {
+            end -= 1;
+        }
+
+        if start != 0 {
+            folded.push_str("\r\n ");
+        }
+        folded.push_str(&line[start..end]);
+        start = end;
+    }
+
+    folded
+}
+
+/// Escape a TEXT value per RFC 5545 section 3.3.11: backslash, comma and
+/// semicolon are backslash-escaped, and newlines become a literal `\n`.
+fn escape_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ',' => out.push_str("\\,"),
+            ';' => out.push_str("\\;"),
+            '\n' => out.push_str("\\n"),
+            '\r' => (),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn line(name: &str, value: &str) -> String {
+    fold_line(&format!("{}:{}", name, value))
+}
+
+fn event(recording: &Recording) -> String {
+    let mut mtime = recording.mtime.clone();
+    let dtstart = mtime.format("%Y%m%dT%H%M%S").to_string();
+    mtime = mtime + chrono::Duration::minutes(recording.duration);
+    let dtend = mtime.format("%Y%m%dT%H%M%S").to_string();
+
+    let description = recording
+        .files
+        .iter()
+        .map(|f| format!("{}: {}", f.outfile, f.url))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut event = String::new();
+
+    event.push_str("BEGIN:VEVENT\r\n");
+    event.push_str(&line("UID", &escape_text(&recording.uuid)));
+    event.push_str("\r\n");
+    event.push_str(&line(
+        &format!("DTSTART;TZID={}", recording.timezone),
+        &dtstart,
+    ));
+    event.push_str("\r\n");
+    event.push_str(&line(
+        &format!("DTEND;TZID={}", recording.timezone),
+        &dtend,
+    ));
+    event.push_str("\r\n");
+    event.push_str(&line("SUMMARY", &escape_text(&recording.topic)));
+    event.push_str("\r\n");
+    event.push_str(&line("DESCRIPTION", &escape_text(&description)));
+    event.push_str("\r\n");
+    event.push_str("END:VEVENT\r\n");
+
+    event
+}
+
+/// Build a complete VCALENDAR containing one VEVENT per meeting in
+/// `recordings`.
+pub(crate) fn build_calendar(recordings: &[Recording]) -> String {
+    let mut out = String::new();
+
+    out.push_str("BEGIN:VCALENDAR\r\n");
+    out.push_str("VERSION:2.0\r\n");
+    out.push_str(&line("PRODID", PRODID));
+    out.push_str("\r\n");
+
+    for recording in recordings {
+        out.push_str(&event(recording));
+    }
+
+    out.push_str("END:VCALENDAR\r\n");
+
+    out
+}
+
+/// Extract the existing VEVENT blocks out of a previously-written
+/// calendar, so a fresh run can add to it rather than clobber it.
+fn existing_events(calendar: &str) -> Vec<&str> {
+    let mut events = Vec::new();
+    let mut rest = calendar;
+
+    while let Some(start) = rest.find("BEGIN:VEVENT") {
+        let rest_from_start = &rest[start..];
+        if let Some(end) = rest_from_start.find("END:VEVENT") {
+            let end = end + "END:VEVENT".len();
+            events.push(&rest_from_start[..end]);
+            rest = &rest_from_start[end..];
+        } else {
+            break;
+        }
+    }
+
+    events
+}
+
+/// Build a VCALENDAR containing every meeting in `recordings`, plus
+/// whatever VEVENTs were already present in `existing` (if any),
+/// skipping any whose UID we're about to re-add.
+pub(crate) fn merge_calendar(existing: Option<&str>, recordings: &[Recording]) -> String {
+    let new_uids: Vec<String> = recordings
+        .iter()
+        .map(|r| format!("UID:{}", escape_text(&r.uuid)))
+        .collect();
+
+    let mut out = String::new();
+
+    out.push_str("BEGIN:VCALENDAR\r\n");
+    out.push_str("VERSION:2.0\r\n");
+    out.push_str(&line("PRODID", PRODID));
+    out.push_str("\r\n");
+
+    if let Some(calendar) = existing {
+        for old_event in existing_events(calendar) {
+            if !new_uids.iter().any(|uid| old_event.contains(uid.as_str())) {
+                out.push_str(old_event);
+                out.push_str("\r\n");
+            }
+        }
+    }
+
+    for recording in recordings {
+        out.push_str(&event(recording));
+    }
+
+    out.push_str("END:VCALENDAR\r\n");
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fold_line() {
+        let short = "DTSTART:20200101T100000";
+        assert_eq!(fold_line(short), short);
+
+        let long = format!("DESCRIPTION:{}", "x".repeat(100));
+        let folded = fold_line(&long);
+        assert!(folded.contains("\r\n "));
+        for l in folded.split("\r\n") {
+            assert!(l.len() <= 75);
+        }
+    }
+
+    #[test]
+    fn test_escape_text() {
+        assert_eq!(escape_text("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    }
+}
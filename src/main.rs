@@ -19,6 +19,9 @@ use std::io;
 use std::path;
 use std::process;
 use std::str::FromStr;
+use std::sync;
+use std::thread;
+use std::time;
 
 use chrono::{DateTime, Duration, Local, Timelike, Utc};
 use chrono_tz::Tz;
@@ -27,22 +30,45 @@ use env_logger;
 use failure::{err_msg, Error, Fail};
 use jsonwebtoken;
 use lambda_runtime;
-use lettre::{SendmailTransport, Transport};
-use lettre_email::{EmailBuilder, Mailbox};
+use lettre_email::Mailbox;
 use log::debug;
+use rand::Rng;
 use reqwest;
 use rusoto_core;
-use rusoto_ses::{Ses, SesClient};
 use rusoto_ssm::{Ssm, SsmClient};
 use serde;
 use serde_json;
 use structopt::StructOpt;
 
+mod ical;
+mod notify;
+mod rules;
+mod search;
+mod transcript;
+mod webhook;
+
+use notify::NotifyConfig;
+use rules::Rule;
+
 #[derive(Debug, StructOpt)]
 struct Opt {
     /// Alternative config file
     #[structopt(name = "config", long, short, value_name = "FILE", parse(from_os_str))]
     config_file: Option<path::PathBuf>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Listen for Zoom's recording.completed webhook instead of polling
+    Serve,
+    /// Search downloaded transcripts for a query
+    Search {
+        #[structopt(name = "query")]
+        query: String,
+    },
 }
 
 #[derive(Debug, Fail)]
@@ -52,13 +78,25 @@ struct NoHomeDirError;
 /*
  * This only exists because we're not allowed to impl Deserialize for lettre_email::Mailbox.
  */
-#[derive(Debug)]
-struct EmailAddress(Mailbox);
+#[derive(Debug, Clone)]
+pub(crate) struct EmailAddress(pub(crate) Mailbox);
 
 fn default_days() -> i64 {
     1
 }
 
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_output_template() -> String {
+    "{date}/{time}.{type}".to_string()
+}
+
+fn default_listen_addr() -> String {
+    "0.0.0.0:8080".to_string()
+}
+
 // FIXME: shouldn't require output_dir for lambda mode
 
 #[derive(serde::Deserialize, Debug)]
@@ -70,20 +108,49 @@ struct Config {
     output_dir: String,
     #[serde(default = "default_days")]
     days: i64,
-    notify: Option<EmailAddress>,
+    /// number of recording files to download in parallel; the `serve`
+    /// listener also uses it as the number of meetings handled at once,
+    /// each with its own pool of this many downloads
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    /// where to send the list of new recordings; may name several backends
+    #[serde(default)]
+    notify: Vec<NotifyConfig>,
+    /// include/exclude rules applied to each recording file, in order
+    #[serde(default)]
+    rules: Vec<Rule>,
+    /// path, relative to output_dir, for each downloaded file; see
+    /// rules::expand_template for the supported {variables}
+    #[serde(default = "default_output_template")]
+    output_template: String,
+    /// required to run the `serve` webhook listener
+    webhook_secret: Option<String>,
+    /// address the `serve` webhook listener binds to
+    #[serde(default = "default_listen_addr")]
+    listen_addr: String,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
 struct RecordingFile {
     outfile: String,
     url: String,
+    /// lower-cased Zoom file_type, e.g. "mp4", "transcript"
+    file_type: String,
 }
 
+/// All the recording files captured for a single Zoom meeting.
 #[derive(serde::Serialize, Debug, Clone)]
 struct Recording {
+    uuid: String,
+    topic: String,
     date: String,
     time: String,
-    file: RecordingFile,
+    timezone: String,
+    /// meeting length in minutes, as reported by Zoom
+    duration: i64,
+    #[serde(skip)]
+    mtime: DateTime<Tz>,
+    files: Vec<RecordingFile>,
 }
 
 #[derive(serde::Serialize, Clone)]
@@ -115,8 +182,12 @@ struct ZoomRecordingFile {
 
 #[derive(serde::Deserialize, Debug)]
 struct ZoomMeeting {
+    uuid: String,
+    topic: String,
     start_time: String,
     timezone: String,
+    /// meeting length in minutes
+    duration: i64,
     recording_files: Vec<ZoomRecordingFile>,
 }
 
@@ -197,139 +268,268 @@ fn round_time_to_hour(mtime: &mut DateTime<Tz>) {
     }
 }
 
-fn create_meeting_dir(config: &Config, date: &str) -> io::Result<path::PathBuf> {
-    let mut dir = path::PathBuf::from(&config.output_dir);
-    dir.push(date);
+/*
+ * outfile is a path relative to output_dir, potentially with directory
+ * components of its own (see rules::expand_template); make sure they
+ * exist before anyone tries to create the file itself.
+ */
+fn resolve_outfile(config: &Config, outfile: &str) -> io::Result<path::PathBuf> {
+    // PathBuf::push replaces the whole path instead of appending when
+    // given an absolute component, so a leading separator here would
+    // silently discard output_dir; strip it defensively even though
+    // rules::expand_template already sanitizes each templated variable.
+    let outfile = outfile.trim_start_matches(path::MAIN_SEPARATOR);
+
+    let mut path = path::PathBuf::from(&config.output_dir);
+    path.push(outfile);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    Ok(path)
+}
+
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_MS: u64 = 500;
 
-    fs::create_dir_all(&dir)?;
-    Ok(dir)
+fn part_path(outfile: &path::Path) -> path::PathBuf {
+    let mut part = outfile.as_os_str().to_owned();
+    part.push(".part");
+    path::PathBuf::from(part)
 }
 
-fn download(client: &reqwest::Client, url: &str, outfile: &path::PathBuf) -> Result<(), Error> {
-    let mut out = fs::File::create(outfile)?;
-    let mut resp = client.get(url).send()?;
+/*
+ * Exponential backoff with jitter, capped so a flaky connection doesn't
+ * leave us sleeping for minutes between attempts.
+ */
+fn backoff_delay(attempt: u32) -> time::Duration {
+    let base = RETRY_BASE_DELAY_MS * 2u64.pow(attempt.min(5));
+    let jitter = rand::thread_rng().gen_range(0, base / 2 + 1);
+
+    time::Duration::from_millis(base + jitter)
+}
+
+/*
+ * Stream a single attempt at url into part_file, resuming from
+ * part_file's current length via a Range request if it already exists.
+ * Falls back to a full download if the server doesn't honour the range.
+ */
+fn download_attempt(client: &reqwest::Client, url: &str, part_file: &path::Path) -> Result<(), Error> {
+    let resume_from = fs::metadata(part_file).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from));
+    }
+
+    let resp = request.send()?;
+
+    // a stale or corrupt .part file can be longer than the resource now
+    // is (e.g. it changed server-side), which the server reports as 416
+    // rather than honouring our Range; since the .part file can never
+    // shrink on its own, truncate it and restart the download from
+    // scratch rather than repeating the same doomed Range request forever.
+    if resume_from > 0 && resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        debug!("Range not satisfiable for {}, restarting from scratch\n", url);
+        fs::File::create(part_file)?;
+        return download_attempt(client, url, part_file);
+    }
+
+    let mut resp = resp.error_for_status()?;
+
+    let mut out = if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        debug!("Resuming {} from byte {}\n", url, resume_from);
+        fs::OpenOptions::new().append(true).open(part_file)?
+    } else {
+        debug!("Downloading {} from the start\n", url);
+        fs::File::create(part_file)?
+    };
 
-    debug!("Downloading {}\n", url);
     io::copy(&mut resp, &mut out)?;
-    debug!("Downloading {} completed\n", url);
 
     Ok(())
 }
 
+/*
+ * Download url to outfile, retrying transient failures with backoff and
+ * resuming from a `.part` file left over from an earlier attempt. The
+ * `.part` file is only renamed into place once it's known to be complete.
+ */
+fn download(client: &reqwest::Client, url: &str, outfile: &path::Path) -> Result<(), Error> {
+    let part_file = part_path(outfile);
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match download_attempt(client, url, &part_file) {
+            Ok(()) => {
+                fs::rename(&part_file, outfile)?;
+                debug!("Downloading {} completed\n", url);
+                return Ok(());
+            }
+            Err(err) if attempt == MAX_ATTEMPTS => return Err(err),
+            Err(err) => {
+                let delay = backoff_delay(attempt);
+                debug!(
+                    "Download of {} failed on attempt {}: {}, retrying in {:?}\n",
+                    url, attempt, err, delay
+                );
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+/*
+ * Download every recording file, spreading the work over a small pool
+ * of worker threads so a slow or stalled transfer doesn't block the
+ * rest of the batch.
+ */
 fn download_meetings(client: &reqwest::Client, config: &Config, rlist: &Recordings) {
+    let mut jobs = Vec::new();
+
     for recording in &rlist.recordings {
-        let dir = create_meeting_dir(&config, &recording.date).unwrap();
-        let mut outfile = dir;
-        outfile.push(&recording.file.outfile);
+        for file in &recording.files {
+            let outfile = resolve_outfile(&config, &file.outfile).unwrap();
 
-        if outfile.exists() {
-            continue;
-        }
+            if outfile.exists() {
+                continue;
+            }
 
-        println!(
-            "Downloading recording file to {}",
-            outfile.to_string_lossy()
-        );
-        download(&client, &recording.file.url, &outfile).unwrap();
+            jobs.push((outfile, file.url.clone()));
+        }
     }
-}
 
-fn process_meeting(rlist: &mut Recordings, meeting: &ZoomMeeting, mtime: &DateTime<Tz>) {
-    let date = mtime.format("%Y-%m-%d").to_string();
+    let jobs = sync::Arc::new(sync::Mutex::new(jobs.into_iter()));
+    let workers = config.concurrency.max(1);
 
-    for recording in &meeting.recording_files {
-        let time = mtime.format("%H.%M").to_string();
-        let outfile = time.clone() + "." + &recording.file_type.to_ascii_lowercase();
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let jobs = sync::Arc::clone(&jobs);
+            let client = client.clone();
 
-        let recording = Recording {
-            time,
-            date: date.clone(),
-            file: RecordingFile {
-                outfile,
-                url: recording.download_url.clone(),
-            },
-        };
+            thread::spawn(move || loop {
+                let job = jobs.lock().unwrap().next();
+
+                let (outfile, url) = match job {
+                    Some(job) => job,
+                    None => break,
+                };
 
-        debug!("Adding recording {:#?}\n", recording);
+                println!(
+                    "Downloading recording file to {}",
+                    outfile.to_string_lossy()
+                );
+
+                if let Err(err) = download(&client, &url, &outfile) {
+                    eprintln!("Failed to download {}: {}", url, err);
+                }
+            })
+        })
+        .collect();
 
-        rlist.recordings.push(recording);
+    for handle in handles {
+        handle.join().unwrap();
     }
 }
 
-fn send_ses(recipient: &Mailbox, subject: &str, body: &str) {
-    // FIXME: region hard-coded here as us-east-2 has no SES
-    let sesclient = SesClient::new(rusoto_core::Region::UsEast1);
-    let to = format!("{}", recipient);
-
-    let result = sesclient
-        .send_email(rusoto_ses::SendEmailRequest {
-            destination: rusoto_ses::Destination {
-                to_addresses: Some(vec![to]),
-                ..rusoto_ses::Destination::default()
-            },
-            message: rusoto_ses::Message {
-                subject: rusoto_ses::Content {
-                    data: subject.to_string(),
-                    ..rusoto_ses::Content::default()
-                },
-                body: rusoto_ses::Body {
-                    text: Some(rusoto_ses::Content {
-                        data: body.to_string(),
-                        ..rusoto_ses::Content::default()
-                    }),
-                    ..rusoto_ses::Body::default()
+/*
+ * Write (or extend) a recordings.ics in output_dir, so the directory can
+ * be subscribed to as a calendar of what was recorded and where.
+ */
+fn write_calendar(config: &Config, rlist: &Recordings) -> Result<(), Error> {
+    let mut ics_file = path::PathBuf::from(&config.output_dir);
+    ics_file.push("recordings.ics");
+
+    let existing = fs::read_to_string(&ics_file).ok();
+    let calendar = ical::merge_calendar(existing.as_deref(), &rlist.recordings);
+
+    fs::write(&ics_file, calendar)?;
+
+    Ok(())
+}
+
+fn process_meeting(rlist: &mut Recordings, config: &Config, meeting: &ZoomMeeting, mtime: &DateTime<Tz>) {
+    let date = mtime.format("%Y-%m-%d").to_string();
+    let time = mtime.format("%H.%M").to_string();
+    let weekday = mtime.format("%a").to_string();
+
+    let files = meeting
+        .recording_files
+        .iter()
+        .filter(|file| {
+            rules::is_included(&config.rules, &meeting.topic, &file.file_type, &weekday)
+        })
+        .map(|file| {
+            let file_type = file.file_type.to_ascii_lowercase();
+            let outfile = rules::expand_template(
+                &config.output_template,
+                &rules::TemplateVars {
+                    date: &date,
+                    time: &time,
+                    topic: &meeting.topic,
+                    file_type: &file_type,
+                    user: &config.user,
                 },
-            },
-            source: "zoom-lomax@movementarian.org".to_string(),
-            ..rusoto_ses::SendEmailRequest::default()
+            );
+
+            RecordingFile {
+                outfile,
+                url: file.download_url.clone(),
+                file_type,
+            }
         })
-        .sync();
+        .collect::<Vec<_>>();
 
-    if result.is_err() {
-        eprintln!("Couldn't send email to {}: {:?}", recipient, result);
+    if files.is_empty() {
+        debug!("All recording files for {:?} excluded by rules\n", meeting.topic);
+        return;
     }
+
+    let recording = Recording {
+        uuid: meeting.uuid.clone(),
+        topic: meeting.topic.clone(),
+        date,
+        time,
+        timezone: meeting.timezone.clone(),
+        duration: meeting.duration,
+        mtime: *mtime,
+        files,
+    };
+
+    debug!("Adding recording {:#?}\n", recording);
+
+    rlist.recordings.push(recording);
 }
 
 fn send_notification(config: &Config, is_lambda: bool, rlist: &Recordings) {
     let now = Local::now().format("%Y-%m-%d");
     let subject = format!("{}: new Zoom recordings", now);
-    let recipient = &config.notify.as_ref().unwrap().0;
 
     let mut body = "Zoom recordings are available:\n\n".to_owned();
 
     for recording in &rlist.recordings {
-        if is_lambda {
-            body += &format!(
-                "{}/{}: {}\n",
-                recording.date, recording.file.outfile, recording.file.url
-            );
-        } else {
-            body += &format!(
-                "{}/{}/{}\n",
-                config.output_dir, recording.date, recording.file.outfile
-            );
+        for file in &recording.files {
+            if is_lambda {
+                body += &format!("{}: {}\n", file.outfile, file.url);
+            } else {
+                body += &format!("{}/{}\n", config.output_dir, file.outfile);
+            }
         }
     }
 
-    debug!("Sending notification to {:?}\n", recipient);
-
     if is_lambda {
-        send_ses(recipient, &subject, &body);
-        return;
+        body += "\n";
+        body += &ical::build_calendar(&rlist.recordings);
     }
 
-    let email = EmailBuilder::new()
-        .to(recipient.clone())
-        .from("zoom-lomax@movementarian.org")
-        .subject(subject)
-        .text(body)
-        .build()
-        .unwrap();
-
-    let result = SendmailTransport::new().send(email.into());
+    for backend in &config.notify {
+        debug!("Sending notification via {:?}\n", backend);
 
-    if result.is_err() {
-        eprintln!("Couldn't send email to {}: {:?}", recipient, result);
+        if let Err(err) = backend.build().notify(&subject, &body) {
+            eprintln!("Couldn't send notification: {}", err);
+        }
     }
 }
 
@@ -366,7 +566,11 @@ fn run(config: Config, is_lambda: bool) -> Result<Recordings, Error> {
         format!("Bearer {}", token).parse().unwrap(),
     );
 
-    let client = reqwest::Client::new();
+    /*
+     * Accept compressed responses for the (often large) recording
+     * downloads; brotli requires the reqwest "brotli" feature.
+     */
+    let client = reqwest::Client::builder().gzip(true).brotli(true).build()?;
 
     let meetings = get_meetings(&client, &config, &headers)?;
 
@@ -384,14 +588,19 @@ fn run(config: Config, is_lambda: bool) -> Result<Recordings, Error> {
 
         round_time_to_hour(&mut mtime);
 
-        process_meeting(&mut rlist, &meeting, &mtime);
+        process_meeting(&mut rlist, &config, &meeting, &mtime);
     }
 
     if !is_lambda {
         download_meetings(&client, &config, &rlist);
+
+        if !rlist.recordings.is_empty() {
+            write_calendar(&config, &rlist)?;
+            search::index_recordings(&config, &rlist)?;
+        }
     }
 
-    if !rlist.recordings.is_empty() && config.notify.is_some() {
+    if !rlist.recordings.is_empty() && !config.notify.is_empty() {
         send_notification(&config, is_lambda, &rlist);
     }
 
@@ -405,7 +614,11 @@ fn run_cmdline(opt: Opt) -> Result<(), Error> {
 
     let config = read_config(fs::File::open(&config_file)?)?;
 
-    run(config, false).map(|_r| ())
+    match opt.cmd {
+        Some(Command::Serve) => webhook::serve(config),
+        Some(Command::Search { query }) => search::run_search(&config, &query),
+        None => run(config, false).map(|_r| ()),
+    }
 }
 
 fn run_lambda(
@@ -488,7 +701,18 @@ mod test {
             "api_secret": "secret",
             "output_dir": "/home/me/dir",
             "user": "user@example.com",
-            "notify": "<user@foo.com"
+            "notify": [{"backend": "sendmail", "to": "<user@foo.com"}]
+        }"#
+            .as_bytes(),
+        )
+        .expect_err("should fail");
+        read_config(
+            r#"{
+            "api_key": "key",
+            "api_secret": "secret",
+            "output_dir": "/home/me/dir",
+            "user": "user@example.com",
+            "notify": [{"backend": "carrier-pigeon", "to": "user@foo.com"}]
         }"#
             .as_bytes(),
         )
@@ -510,7 +734,7 @@ mod test {
                     "api_secret": "secret",
                     "output_dir": "/home/me/dir",
                     "user": "user@example.com",
-                    "notify": "user@"
+                    "notify": [{"backend": "sendmail", "to": "user@"}]
                 }"#.as_bytes()).expect_err("should fail");
         */
     }
@@ -533,7 +757,10 @@ mod test {
             "api_secret": "secret",
             "output_dir": "/home/me/dir",
             "user": "user@example.com",
-            "notify": "My name <user@example.com>",
+            "notify": [
+                {"backend": "sendmail", "to": "My name <user@example.com>"},
+                {"backend": "matrix", "homeserver": "https://matrix.org", "room_id": "!abc:matrix.org", "access_token": "tok"}
+            ],
             "days": 4
         }"#
             .as_bytes(),
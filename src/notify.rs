@@ -0,0 +1,148 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! Pluggable backends for telling someone new recordings showed up:
+//! email (via sendmail or SES) or a Matrix room.
+
+use lettre::{SendmailTransport, Transport};
+use lettre_email::{EmailBuilder, Mailbox};
+use log::debug;
+use rusoto_ses::{Ses, SesClient};
+
+use crate::EmailAddress;
+
+/// A way of telling someone that new recordings are available.
+pub(crate) trait Notifier {
+    fn notify(&self, subject: &str, body: &str) -> Result<(), failure::Error>;
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub(crate) enum NotifyConfig {
+    Sendmail { to: EmailAddress },
+    Ses { to: EmailAddress },
+    Matrix {
+        homeserver: String,
+        room_id: String,
+        access_token: String,
+    },
+}
+
+impl NotifyConfig {
+    pub(crate) fn build(&self) -> Box<dyn Notifier> {
+        match self {
+            NotifyConfig::Sendmail { to } => Box::new(Sendmail { to: to.0.clone() }),
+            NotifyConfig::Ses { to } => Box::new(SesNotifier { to: to.0.clone() }),
+            NotifyConfig::Matrix {
+                homeserver,
+                room_id,
+                access_token,
+            } => Box::new(Matrix {
+                homeserver: homeserver.clone(),
+                room_id: room_id.clone(),
+                access_token: access_token.clone(),
+            }),
+        }
+    }
+}
+
+pub(crate) struct Sendmail {
+    to: Mailbox,
+}
+
+impl Notifier for Sendmail {
+    fn notify(&self, subject: &str, body: &str) -> Result<(), failure::Error> {
+        let email = EmailBuilder::new()
+            .to(self.to.clone())
+            .from("zoom-lomax@movementarian.org")
+            .subject(subject)
+            .text(body)
+            .build()?;
+
+        SendmailTransport::new()
+            .send(email.into())
+            .map_err(|err| failure::err_msg(format!("couldn't send email to {}: {:?}", self.to, err)))
+    }
+}
+
+pub(crate) struct SesNotifier {
+    to: Mailbox,
+}
+
+impl Notifier for SesNotifier {
+    fn notify(&self, subject: &str, body: &str) -> Result<(), failure::Error> {
+        // FIXME: region hard-coded here as us-east-2 has no SES
+        let sesclient = SesClient::new(rusoto_core::Region::UsEast1);
+        let to = format!("{}", self.to);
+
+        sesclient
+            .send_email(rusoto_ses::SendEmailRequest {
+                destination: rusoto_ses::Destination {
+                    to_addresses: Some(vec![to]),
+                    ..rusoto_ses::Destination::default()
+                },
+                message: rusoto_ses::Message {
+                    subject: rusoto_ses::Content {
+                        data: subject.to_string(),
+                        ..rusoto_ses::Content::default()
+                    },
+                    body: rusoto_ses::Body {
+                        text: Some(rusoto_ses::Content {
+                            data: body.to_string(),
+                            ..rusoto_ses::Content::default()
+                        }),
+                        ..rusoto_ses::Body::default()
+                    },
+                },
+                source: "zoom-lomax@movementarian.org".to_string(),
+                ..rusoto_ses::SendEmailRequest::default()
+            })
+            .sync()
+            .map_err(|err| failure::err_msg(format!("couldn't send email to {}: {:?}", self.to, err)))
+    }
+}
+
+/// Posts to a Matrix room via the client-server API:
+/// https://spec.matrix.org/v1.9/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid
+pub(crate) struct Matrix {
+    homeserver: String,
+    room_id: String,
+    access_token: String,
+}
+
+impl Notifier for Matrix {
+    fn notify(&self, subject: &str, body: &str) -> Result<(), failure::Error> {
+        let txn_id = format!("{}-{}", std::process::id(), rand::random::<u64>());
+
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver.trim_end_matches('/'),
+            self.room_id,
+            txn_id
+        );
+
+        debug!("Posting to Matrix room {}\n", self.room_id);
+
+        let client = reqwest::Client::new();
+        let resp = client
+            .put(&url)
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({
+                "msgtype": "m.text",
+                "body": format!("{}\n\n{}", subject, body),
+            }))
+            .send()?
+            .error_for_status()?;
+
+        debug!("Matrix response: {:?}\n", resp);
+
+        Ok(())
+    }
+}
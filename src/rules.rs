@@ -0,0 +1,176 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! Rule-based include/exclude filtering and output path templating for
+//! recording files, in the spirit of the regex-driven rewrite rules a
+//! mail server uses to decide what to keep and where it ends up.
+
+/// What to do with a recording file that a [`Rule`] matches.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Action {
+    Include,
+    Exclude,
+}
+
+/// A single filtering rule. Any field left unset matches everything for
+/// that criterion; rules are evaluated in order and the last one whose
+/// criteria all match wins.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub(crate) struct Rule {
+    /// regex matched against the meeting topic
+    topic: Option<String>,
+    file_type: Option<String>,
+    /// e.g. "Mon", "Tue", matched case-insensitively
+    weekday: Option<String>,
+    action: Action,
+}
+
+fn rule_matches(rule: &Rule, topic: &str, file_type: &str, weekday: &str) -> bool {
+    if let Some(pattern) = &rule.topic {
+        match regex::Regex::new(pattern) {
+            Ok(re) if re.is_match(topic) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(want) = &rule.file_type {
+        if !want.eq_ignore_ascii_case(file_type) {
+            return false;
+        }
+    }
+
+    if let Some(want) = &rule.weekday {
+        if !want.eq_ignore_ascii_case(weekday) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Everything is kept by default; later matching rules override earlier
+/// ones, so a broad "Exclude" rule can be narrowed by a later "Include".
+pub(crate) fn is_included(rules: &[Rule], topic: &str, file_type: &str, weekday: &str) -> bool {
+    let mut keep = true;
+
+    for rule in rules {
+        if rule_matches(rule, topic, file_type, weekday) {
+            keep = rule.action == Action::Include;
+        }
+    }
+
+    keep
+}
+
+pub(crate) struct TemplateVars<'a> {
+    pub(crate) date: &'a str,
+    pub(crate) time: &'a str,
+    pub(crate) topic: &'a str,
+    pub(crate) file_type: &'a str,
+    pub(crate) user: &'a str,
+}
+
+/*
+ * Strip anything that could turn a substituted variable into a path
+ * separator or a directory traversal, so a meeting topic set by a
+ * meeting host can't steer a file outside output_dir.
+ */
+fn sanitize_component(value: &str) -> String {
+    let mut out: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+
+    while out.contains("..") {
+        out = out.replace("..", "_");
+    }
+
+    let out = out.trim().to_string();
+
+    // an empty (or all-whitespace) topic/date/etc would otherwise vanish
+    // from the template entirely, turning "{topic}/{date}/..." into a
+    // leading "/" and letting the rest of the path escape output_dir
+    if out.is_empty() {
+        "_".to_string()
+    } else {
+        out
+    }
+}
+
+/// Expand `{date}`, `{time}`, `{topic}`, `{type}` and `{user}` in
+/// `template`. Any literal `/` in the template is kept as a directory
+/// separator; the substituted values themselves are sanitized.
+pub(crate) fn expand_template(template: &str, vars: &TemplateVars) -> String {
+    template
+        .replace("{date}", &sanitize_component(vars.date))
+        .replace("{time}", &sanitize_component(vars.time))
+        .replace("{topic}", &sanitize_component(vars.topic))
+        .replace("{type}", &sanitize_component(vars.file_type))
+        .replace("{user}", &sanitize_component(vars.user))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand_template_sanitizes() {
+        let vars = TemplateVars {
+            date: "2020-01-01",
+            time: "10.00",
+            topic: "../../etc/passwd",
+            file_type: "mp4",
+            user: "me@example.com",
+        };
+
+        let out = expand_template("{topic}/{date}/{time}.{type}", &vars);
+        assert_eq!(out, "____etc_passwd/2020-01-01/10.00.mp4");
+    }
+
+    #[test]
+    fn test_expand_template_blank_variable_has_no_leading_separator() {
+        let vars = TemplateVars {
+            date: "2020-01-01",
+            time: "10.00",
+            topic: "   ",
+            file_type: "mp4",
+            user: "me@example.com",
+        };
+
+        let out = expand_template("{topic}/{date}/{time}.{type}", &vars);
+        assert!(!out.starts_with('/'));
+        assert_eq!(out, "_/2020-01-01/10.00.mp4");
+    }
+
+    #[test]
+    fn test_rule_precedence() {
+        let rules = vec![
+            Rule {
+                topic: None,
+                file_type: None,
+                weekday: None,
+                action: Action::Exclude,
+            },
+            Rule {
+                topic: Some("standup".to_string()),
+                file_type: None,
+                weekday: None,
+                action: Action::Include,
+            },
+        ];
+
+        assert!(!is_included(&rules, "1:1 with Bob", "mp4", "Mon"));
+        assert!(is_included(&rules, "Daily standup", "mp4", "Mon"));
+    }
+}
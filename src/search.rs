@@ -0,0 +1,252 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! A small on-disk inverted index over meeting transcripts, so
+//! `search <query>` can tell you which recording covered a topic
+//! without anyone having to remember which meeting that was.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use failure::Error;
+
+use crate::{resolve_outfile, Config, Recordings};
+
+const INDEX_FILE: &str = "search-index.json";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct Document {
+    date: String,
+    topic: String,
+    paths: Vec<String>,
+    text: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+struct Index {
+    /// meeting uuid -> document
+    documents: HashMap<String, Document>,
+    /// term -> (meeting uuid -> term frequency)
+    terms: HashMap<String, HashMap<String, u32>>,
+}
+
+fn index_path(config: &Config) -> path::PathBuf {
+    let mut path = path::PathBuf::from(&config.output_dir);
+    path.push(INDEX_FILE);
+    path
+}
+
+fn load(path: &path::Path) -> Index {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save(path: &path::Path, index: &Index) -> Result<(), Error> {
+    fs::write(path, serde_json::to_string_pretty(index)?)?;
+    Ok(())
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_ascii_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+fn add_document(index: &mut Index, uuid: &str, document: Document) {
+    let mut frequencies: HashMap<String, u32> = HashMap::new();
+
+    for token in tokenize(&document.topic).into_iter().chain(tokenize(&document.text)) {
+        *frequencies.entry(token).or_insert(0) += 1;
+    }
+
+    for (term, freq) in frequencies {
+        index
+            .terms
+            .entry(term)
+            .or_insert_with(HashMap::new)
+            .insert(uuid.to_string(), freq);
+    }
+
+    index.documents.insert(uuid.to_string(), document);
+}
+
+fn read_transcript(config: &Config, recording: &crate::Recording) -> Result<Option<Document>, Error> {
+    let transcript_file = match recording.files.iter().find(|f| f.file_type == "transcript") {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let outfile = resolve_outfile(config, &transcript_file.outfile)?;
+    let vtt = fs::read_to_string(&outfile)?;
+    let text = crate::transcript::extract_text(&vtt);
+
+    Ok(Some(Document {
+        date: recording.date.clone(),
+        topic: recording.topic.clone(),
+        paths: recording.files.iter().map(|f| f.outfile.clone()).collect(),
+        text,
+    }))
+}
+
+/*
+ * Parse any transcript files in `rlist` and add them to the persistent
+ * index in `config.output_dir`, so they show up in future searches. A
+ * transcript that failed to download (or doesn't parse) is logged and
+ * skipped rather than failing the whole batch, same as a failed media
+ * download in download_meetings().
+ */
+pub(crate) fn index_recordings(config: &Config, rlist: &Recordings) -> Result<(), Error> {
+    let path = index_path(config);
+    let mut index = load(&path);
+    let mut changed = false;
+
+    for recording in &rlist.recordings {
+        match read_transcript(config, recording) {
+            Ok(Some(document)) => {
+                add_document(&mut index, &recording.uuid, document);
+                changed = true;
+            }
+            Ok(None) => (),
+            Err(err) => eprintln!(
+                "Couldn't index transcript for {:?}: {}",
+                recording.topic, err
+            ),
+        }
+    }
+
+    if changed {
+        save(&path, &index)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Hit {
+    uuid: String,
+    score: u32,
+    document: Document,
+}
+
+/*
+ * pos ± CONTEXT is a byte offset that can land in the middle of a
+ * multi-byte UTF-8 character (transcripts are free-form spoken text,
+ * not ASCII); round the start down and the end up to a valid char
+ * boundary before slicing so non-ASCII names/words don't panic the search subcommand.
+ */
+fn floor_char_boundary(s: &str, mut idx: usize) -> usize {
+    while idx > 0 && !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+fn ceil_char_boundary(s: &str, mut idx: usize) -> usize {
+    while idx < s.len() && !s.is_char_boundary(idx) {
+        idx += 1;
+    }
+    idx
+}
+
+/// A snippet of text around the first occurrence of any query term.
+fn snippet(text: &str, terms: &[String]) -> String {
+    const CONTEXT: usize = 40;
+
+    let lower = text.to_ascii_lowercase();
+    let pos = terms
+        .iter()
+        .filter_map(|term| lower.find(term.as_str()))
+        .min();
+
+    match pos {
+        Some(pos) => {
+            let start = floor_char_boundary(text, pos.saturating_sub(CONTEXT));
+            let end = ceil_char_boundary(text, (pos + CONTEXT).min(text.len()));
+            format!("...{}...", text[start..end].trim())
+        }
+        None => text.chars().take(CONTEXT * 2).collect(),
+    }
+}
+
+/// Run a `search` subcommand: tokenize `query`, rank matching meetings
+/// by summed term frequency, and print each with its file paths and a
+/// snippet of matching context.
+pub(crate) fn run_search(config: &Config, query: &str) -> Result<(), Error> {
+    let index = load(&index_path(config));
+    let terms = tokenize(query);
+
+    let mut scores: HashMap<String, u32> = HashMap::new();
+
+    for term in &terms {
+        if let Some(postings) = index.terms.get(term) {
+            for (uuid, freq) in postings {
+                *scores.entry(uuid.clone()).or_insert(0) += freq;
+            }
+        }
+    }
+
+    let mut hits: Vec<Hit> = scores
+        .into_iter()
+        .filter_map(|(uuid, score)| {
+            index.documents.get(&uuid).map(|document| Hit {
+                uuid,
+                score,
+                document: document.clone(),
+            })
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.cmp(&a.score));
+
+    if hits.is_empty() {
+        println!("No matching recordings for {:?}", query);
+        return Ok(());
+    }
+
+    for hit in hits {
+        println!(
+            "{} - {} ({})",
+            hit.document.date, hit.document.topic, hit.uuid
+        );
+        for path in &hit.document.paths {
+            println!("    {}/{}", config.output_dir, path);
+        }
+        println!("    {}", snippet(&hit.document.text, &terms));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Daily Standup, #2!"), vec!["daily", "standup", "2"]);
+    }
+
+    #[test]
+    fn test_snippet_around_match() {
+        let out = snippet("the quarterly budgets are due", &["budgets".to_string()]);
+        assert!(out.contains("budgets"));
+    }
+
+    #[test]
+    fn test_snippet_does_not_panic_on_multibyte_text() {
+        let text = format!("{}budgets are due", "中".repeat(30));
+        let out = snippet(&text, &["budgets".to_string()]);
+        assert!(out.contains("budgets"));
+    }
+}
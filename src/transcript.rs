@@ -0,0 +1,58 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! Pull the spoken text out of a Zoom transcript (WebVTT), discarding
+//! everything that's only useful for playback: the `WEBVTT` header, cue
+//! indices, and `HH:MM:SS.mmm --> HH:MM:SS.mmm` timing lines.
+
+/// Extract the cue text from a VTT document as a single line, with each
+/// kept text line joined to the next by a space.
+pub(crate) fn extract_text(vtt: &str) -> String {
+    let mut cues = Vec::new();
+
+    for line in vtt.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line == "WEBVTT" {
+            continue;
+        }
+
+        // a timing line, e.g. "00:00:02.000 --> 00:00:05.500 align:start"
+        if line.contains("-->") {
+            continue;
+        }
+
+        // a bare cue index
+        if line.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        cues.push(line);
+    }
+
+    cues.join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_text() {
+        let vtt = "WEBVTT\n\n1\n00:00:00.000 --> 00:00:02.000\nHello everyone\n\n2\n00:00:02.000 --> 00:00:05.500 align:start position:10%\nWelcome to the meeting\n";
+
+        assert_eq!(extract_text(vtt), "Hello everyone Welcome to the meeting");
+    }
+
+    #[test]
+    fn test_extract_text_empty() {
+        assert_eq!(extract_text("WEBVTT\n"), "");
+    }
+}
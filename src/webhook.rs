@@ -0,0 +1,268 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 John Levon <levon@movementarian.org>
+ */
+
+//! Long-running webhook listener, as an alternative to polling the
+//! recordings-list endpoint: Zoom POSTs a `recording.completed` event as
+//! soon as a recording is ready, so we can download it immediately
+//! instead of waiting for the next scheduled poll.
+//!
+//! https://marketplace.zoom.us/docs/api-reference/webhook-reference/
+
+use std::io::Read;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use failure::{err_msg, Error};
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::Sha256;
+
+use crate::{process_meeting, Config, Recordings, ZoomMeeting};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(serde::Deserialize, Debug)]
+struct WebhookEvent {
+    event: String,
+    payload: serde_json::Value,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct UrlValidationPayload {
+    #[serde(rename = "plainToken")]
+    plain_token: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct RecordingCompletedPayload {
+    object: ZoomMeeting,
+}
+
+fn hex_hmac(secret: &str, message: &str) -> Result<String, Error> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).map_err(|_| err_msg("bad webhook secret"))?;
+    mac.update(message.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/*
+ * Zoom signs each delivery as "v0=<hex hmac>" of "v0:{timestamp}:{body}",
+ * keyed by the webhook secret token. Comparison happens inside
+ * `Mac::verify_slice`, which is constant-time.
+ */
+fn verify_signature(
+    secret: &str,
+    timestamp: &str,
+    body: &str,
+    signature: &str,
+) -> Result<bool, Error> {
+    let signature = match signature.strip_prefix("v0=") {
+        Some(sig) => sig,
+        None => return Ok(false),
+    };
+
+    let expected = match hex::decode(signature) {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(false),
+    };
+
+    let message = format!("v0:{}:{}", timestamp, body);
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).map_err(|_| err_msg("bad webhook secret"))?;
+    mac.update(message.as_bytes());
+
+    Ok(mac.verify_slice(&expected).is_ok())
+}
+
+/*
+ * The actual download/calendar/index/notify work for one meeting. This
+ * runs on a worker thread, off the request-handling path, so it can
+ * take as long as it likes without holding up Zoom's delivery or
+ * blocking the next event from being accepted.
+ */
+fn process_recording(config: &Config, client: &reqwest::Client, meeting: &ZoomMeeting) {
+    let tz: chrono_tz::Tz = match meeting.timezone.parse().map_err(err_msg) {
+        Ok(tz) => tz,
+        Err(err) => return eprintln!("Bad timezone {:?}: {}", meeting.timezone, err),
+    };
+
+    let mut mtime = match chrono::DateTime::parse_from_rfc3339(&meeting.start_time) {
+        Ok(t) => t.with_timezone(&tz),
+        Err(err) => return eprintln!("Bad start_time {:?}: {}", meeting.start_time, err),
+    };
+
+    crate::round_time_to_hour(&mut mtime);
+
+    let mut rlist = Recordings {
+        recordings: Vec::new(),
+    };
+    process_meeting(&mut rlist, config, meeting, &mtime);
+    crate::download_meetings(client, config, &rlist);
+
+    if rlist.recordings.is_empty() {
+        return;
+    }
+
+    if let Err(err) = crate::write_calendar(config, &rlist) {
+        eprintln!("Couldn't write calendar: {}", err);
+    }
+
+    if let Err(err) = crate::search::index_recordings(config, &rlist) {
+        eprintln!("Couldn't update search index: {}", err);
+    }
+
+    for backend in &config.notify {
+        if let Err(err) = backend
+            .build()
+            .notify("New Zoom recording", "a new recording was downloaded")
+        {
+            eprintln!("Couldn't send notification: {}", err);
+        }
+    }
+}
+
+/*
+ * A small pool of worker threads pulls meetings off this channel and
+ * processes them, mirroring the download worker pool in
+ * download_meetings(); this is what keeps one big recording from
+ * blocking every other delivery behind it.
+ */
+fn spawn_workers(
+    config: Arc<Config>,
+    client: reqwest::Client,
+    workers: usize,
+) -> mpsc::Sender<ZoomMeeting> {
+    let (tx, rx) = mpsc::channel::<ZoomMeeting>();
+    let rx = Arc::new(Mutex::new(rx));
+
+    for _ in 0..workers.max(1) {
+        let rx = Arc::clone(&rx);
+        let config = Arc::clone(&config);
+        let client = client.clone();
+
+        thread::spawn(move || loop {
+            let meeting = match rx.lock().unwrap().recv() {
+                Ok(meeting) => meeting,
+                Err(_) => break,
+            };
+
+            process_recording(&config, &client, &meeting);
+        });
+    }
+
+    tx
+}
+
+fn handle_request(
+    secret: &str,
+    queue: &mpsc::Sender<ZoomMeeting>,
+    request: &mut tiny_http::Request,
+) -> Result<tiny_http::Response<std::io::Cursor<Vec<u8>>>, Error> {
+    let timestamp = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("x-zm-request-timestamp"))
+        .map(|h| h.value.as_str().to_owned())
+        .unwrap_or_default();
+    let signature = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("x-zm-signature"))
+        .map(|h| h.value.as_str().to_owned())
+        .unwrap_or_default();
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+
+    if !verify_signature(secret, &timestamp, &body, &signature)? {
+        debug!("Rejecting webhook delivery with bad signature\n");
+        return Ok(tiny_http::Response::from_string("forbidden").with_status_code(403));
+    }
+
+    let event: WebhookEvent = serde_json::from_str(&body)?;
+
+    debug!("Got webhook event {:?}\n", event.event);
+
+    match event.event.as_str() {
+        "endpoint.url_validation" => {
+            let payload: UrlValidationPayload = serde_json::from_value(event.payload)?;
+            let encrypted_token = hex_hmac(secret, &payload.plain_token)?;
+
+            let response = serde_json::json!({
+                "plainToken": payload.plain_token,
+                "encryptedToken": encrypted_token,
+            });
+
+            Ok(tiny_http::Response::from_string(response.to_string()))
+        }
+        "recording.completed" => {
+            let payload: RecordingCompletedPayload = serde_json::from_value(event.payload)?;
+
+            // hand off to the worker pool and acknowledge the delivery
+            // immediately; downloading can take minutes and must not
+            // block the next incoming event or risk a Zoom retry.
+            if queue.send(payload.object).is_err() {
+                return Ok(tiny_http::Response::from_string("internal error").with_status_code(500));
+            }
+
+            Ok(tiny_http::Response::from_string("accepted"))
+        }
+        other => {
+            debug!("Ignoring unhandled webhook event {:?}\n", other);
+            Ok(tiny_http::Response::from_string("ignored"))
+        }
+    }
+}
+
+/// Run forever, handling Zoom webhook deliveries as they arrive.
+pub(crate) fn serve(config: Config) -> Result<(), Error> {
+    let secret = config
+        .webhook_secret
+        .clone()
+        .ok_or_else(|| err_msg("webhook_secret must be set to run in serve mode"))?;
+
+    let client = reqwest::Client::builder().gzip(true).brotli(true).build()?;
+    let server = tiny_http::Server::http(config.listen_addr.as_str())
+        .map_err(|err| err_msg(format!("couldn't bind {}: {}", config.listen_addr, err)))?;
+
+    println!("Listening for Zoom webhooks on {}", config.listen_addr);
+
+    let workers = config.concurrency;
+    let queue = spawn_workers(Arc::new(config), client, workers);
+
+    for mut request in server.incoming_requests() {
+        let response = match handle_request(&secret, &queue, &mut request) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Error handling webhook delivery: {}", err);
+                tiny_http::Response::from_string("internal error").with_status_code(500)
+            }
+        };
+
+        if let Err(err) = request.respond(response) {
+            eprintln!("Couldn't respond to webhook delivery: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_signature() {
+        let secret = "shh";
+        let sig = hex_hmac(secret, "v0:12345:{}").unwrap();
+
+        assert!(verify_signature(secret, "12345", "{}", &format!("v0={}", sig)).unwrap());
+        assert!(!verify_signature(secret, "12345", "{}", "v0=deadbeef").unwrap());
+        assert!(!verify_signature(secret, "12345", "{}", "garbage").unwrap());
+    }
+}